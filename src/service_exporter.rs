@@ -1,28 +1,204 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use bollard::container::{ListContainersOptions, LogOutput};
 use bollard::Docker;
 use bollard::errors::Error as BollardError;
 use bollard::exec::{CreateExecOptions, CreateExecResults, StartExecResults};
 use bollard::models::ContainerSummary;
+use futures_util::stream::{self, StreamExt};
 use futures_util::TryStreamExt;
 use log::{debug, info, warn};
 
 const DEFAULT_SERVICE_PORT_PATH: &'static str = "9100/metrics";
 const UNKNOWN_SERVICE_NAME: &'static str = "unknown-service";
+const DEFAULT_SCRAPE_CONCURRENCY: usize = 8;
+const DOCKER_CONNECT_TIMEOUT_SECS: u64 = 120;
+const NETWORK_SCRAPE_TIMEOUT_SECS: u64 = 10;
+/// Network name the ECS agent creates for bridge-mode task networking; the well-known,
+/// stable choice when a container is attached to more than one Docker network.
+const DEFAULT_DOCKER_NETWORK_NAME: &'static str = "ecs-bridge";
+
+/// How to reach the Docker daemon. Honors the same environment variables as the Docker
+/// CLI (`DOCKER_HOST`, `DOCKER_CERT_PATH`, `DOCKER_TLS_VERIFY`) so the exporter can run
+/// either against the local socket or as a sidecar pointed at a remote/TCP daemon.
+#[derive(Clone, Debug, Default)]
+pub struct DockerConnectionConfig {
+	/// Daemon address in `DOCKER_HOST` form, e.g. `tcp://127.0.0.1:2376` or `unix:///var/run/docker.sock`;
+	/// `None` connects to the local default socket (the environment is only read by `from_env`)
+	pub host: Option<String>,
+	/// Directory containing `key.pem`, `cert.pem` and `ca.pem`, used for `tcp://`/`http://` hosts;
+	/// `None` connects without TLS
+	pub tls_cert_path: Option<PathBuf>,
+}
+
+impl DockerConnectionConfig {
+	/// Reads `DOCKER_HOST`, `DOCKER_CERT_PATH` and `DOCKER_TLS_VERIFY` from the process environment.
+	pub fn from_env() -> DockerConnectionConfig {
+		DockerConnectionConfig {
+			host: env::var("DOCKER_HOST").ok(),
+			tls_cert_path: env::var("DOCKER_CERT_PATH").ok()
+				.filter(|_| env::var("DOCKER_TLS_VERIFY").map(|v| v != "").unwrap_or(false))
+				.map(PathBuf::from),
+		}
+	}
+
+	fn connect(&self) -> Result<Docker, BollardError> {
+		let host = match &self.host {
+			Some(host) => host.clone(),
+			None => return Docker::connect_with_socket_defaults(),
+		};
+
+		// `unix://<path>` is the standard form for DOCKER_HOST pointing at a local socket
+		// (e.g. rootless Docker's `unix:///run/user/1000/docker.sock`); strip the scheme so
+		// it's treated as the bare filesystem path connect_with_socket expects
+		if let Some(socket_path) = host.strip_prefix("unix://") {
+			return Docker::connect_with_socket(socket_path, DOCKER_CONNECT_TIMEOUT_SECS, bollard::API_DEFAULT_VERSION);
+		}
+
+		if !host.starts_with("tcp://") && !host.starts_with("http://") {
+			return Docker::connect_with_socket(&host, DOCKER_CONNECT_TIMEOUT_SECS, bollard::API_DEFAULT_VERSION);
+		}
+
+		match &self.tls_cert_path {
+			Some(cert_dir) => Docker::connect_with_ssl(
+				&host,
+				&cert_dir.join("key.pem"),
+				&cert_dir.join("cert.pem"),
+				&cert_dir.join("ca.pem"),
+				DOCKER_CONNECT_TIMEOUT_SECS,
+				bollard::API_DEFAULT_VERSION,
+			),
+			None => Docker::connect_with_http(&host, DOCKER_CONNECT_TIMEOUT_SECS, bollard::API_DEFAULT_VERSION),
+		}
+	}
+}
+
+/// How a container's `/metrics` endpoint is reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrapeMode {
+	/// exec `curl` inside the target container (requires `/bin/curl` to exist in its image)
+	ExecCurl,
+	/// GET the endpoint directly from the exporter process over the container's Docker network
+	DockerNetwork,
+}
+
+impl Default for ScrapeMode {
+	fn default() -> Self {
+		ScrapeMode::ExecCurl
+	}
+}
+
+/// Which `com.amazonaws.ecs.*` Docker labels the ECS agent attaches to a task's containers get
+/// copied onto that container's scraped samples, and what Prometheus label name to give them.
+/// `container_name` is handled separately and always attached; this covers everything else an
+/// operator wants to correlate scraped series with the originating ECS task.
+#[derive(Clone, Debug)]
+pub struct EcsLabelMapping {
+	mappings: Vec<(String, String)>,
+}
+
+impl EcsLabelMapping {
+	pub fn new(mappings: Vec<(String, String)>) -> EcsLabelMapping {
+		EcsLabelMapping { mappings }
+	}
+
+	/// Returns the `(target_label, value)` pairs present on this container, in the configured order.
+	fn resolve(&self, container_labels: &HashMap<String, String>) -> Vec<(String, String)> {
+		self.mappings.iter()
+			.filter_map(|(docker_label, target_label)| container_labels.get(docker_label)
+				.map(|value| (target_label.clone(), value.clone())))
+			.collect()
+	}
+}
+
+impl Default for EcsLabelMapping {
+	fn default() -> Self {
+		EcsLabelMapping::new(vec![
+			("com.amazonaws.ecs.task-arn".to_string(), "task_arn".to_string()),
+			("com.amazonaws.ecs.task-definition-family".to_string(), "task_definition_family".to_string()),
+			("com.amazonaws.ecs.task-definition-version".to_string(), "task_definition_version".to_string()),
+			("com.amazonaws.ecs.cluster".to_string(), "cluster".to_string()),
+		])
+	}
+}
 
 pub struct ServiceMetricsExporter {
 	docker: Docker,
 	label_has_metrics: String,
+	scrape_concurrency: usize,
+	scrape_mode: ScrapeMode,
+	ecs_label_mapping: EcsLabelMapping,
+	docker_network_name: String,
+	http_client: reqwest::Client,
 }
 
 
 impl ServiceMetricsExporter {
-	pub fn new(label_has_metrics: String) -> ServiceMetricsExporter {
-		ServiceMetricsExporter {
-			docker: Docker::connect_with_socket_defaults().unwrap(),
+	pub fn new(label_has_metrics: String) -> Result<ServiceMetricsExporter, BollardError> {
+		ServiceMetricsExporter::with_concurrency(label_has_metrics, DEFAULT_SCRAPE_CONCURRENCY)
+	}
+
+	pub fn with_concurrency(label_has_metrics: String, scrape_concurrency: usize) -> Result<ServiceMetricsExporter, BollardError> {
+		ServiceMetricsExporter::with_options(label_has_metrics, scrape_concurrency, ScrapeMode::default())
+	}
+
+	pub fn with_options(label_has_metrics: String, scrape_concurrency: usize, scrape_mode: ScrapeMode) -> Result<ServiceMetricsExporter, BollardError> {
+		ServiceMetricsExporter::with_docker_connection(label_has_metrics, scrape_concurrency, scrape_mode, DockerConnectionConfig::from_env())
+	}
+
+	pub fn with_docker_connection(
+		label_has_metrics: String,
+		scrape_concurrency: usize,
+		scrape_mode: ScrapeMode,
+		docker_connection: DockerConnectionConfig,
+	) -> Result<ServiceMetricsExporter, BollardError> {
+		ServiceMetricsExporter::with_ecs_label_mapping(label_has_metrics, scrape_concurrency, scrape_mode, docker_connection, EcsLabelMapping::default())
+	}
+
+	pub fn with_ecs_label_mapping(
+		label_has_metrics: String,
+		scrape_concurrency: usize,
+		scrape_mode: ScrapeMode,
+		docker_connection: DockerConnectionConfig,
+		ecs_label_mapping: EcsLabelMapping,
+	) -> Result<ServiceMetricsExporter, BollardError> {
+		ServiceMetricsExporter::with_docker_network_name(
 			label_has_metrics,
-		}
+			scrape_concurrency,
+			scrape_mode,
+			docker_connection,
+			ecs_label_mapping,
+			DEFAULT_DOCKER_NETWORK_NAME.to_string(),
+		)
+	}
+
+	pub fn with_docker_network_name(
+		label_has_metrics: String,
+		scrape_concurrency: usize,
+		scrape_mode: ScrapeMode,
+		docker_connection: DockerConnectionConfig,
+		ecs_label_mapping: EcsLabelMapping,
+		docker_network_name: String,
+	) -> Result<ServiceMetricsExporter, BollardError> {
+		Ok(ServiceMetricsExporter {
+			docker: docker_connection.connect()?,
+			label_has_metrics,
+			// buffer_unordered(0) never polls its underlying stream (its fill loop requires
+			// in_progress_queue.len() < max), so the scrape would hang forever instead of erroring
+			scrape_concurrency: scrape_concurrency.max(1),
+			scrape_mode,
+			ecs_label_mapping,
+			docker_network_name,
+			// a stalled container's metrics endpoint would otherwise hold a buffer_unordered
+			// concurrency slot indefinitely, stalling the whole scrape instead of just that container
+			http_client: reqwest::Client::builder()
+				.timeout(Duration::from_secs(NETWORK_SCRAPE_TIMEOUT_SECS))
+				.build()
+				.expect("failed to build the network-scrape HTTP client"),
+		})
 	}
 
 	pub async fn export_metrics(&self) -> Result<String, warp::Rejection> {
@@ -45,44 +221,174 @@ impl ServiceMetricsExporter {
 
 		let containers = containers.unwrap();
 		debug!("Found {} running containers matching the required label", containers.len());
-		let mut metrics = String::new();
-
-		for container in containers {
-			let container_id = &container.id.clone().unwrap();
-			let aws_container_name = &container.labels.clone()
-				.unwrap_or(HashMap::new())
-				.get("com.amazonaws.ecs.container-name")
-				.unwrap_or(&UNKNOWN_SERVICE_NAME.to_string())
-				.to_string();
-			let curl_exec = self.create_docker_exec_for_curl(container, &container_id).await;
-
-			if let Err(err) = curl_exec {
-				warn!("Failed to create exec in container={:?}, e={:?}", &container_id, err);
-				continue;
-			}
 
-			let exec_id = curl_exec.unwrap().id;
-			let curl_output = self.start_curl_exec_return_logs(container_id, &exec_id).await;
-			let exit_code: i64 = match self.docker.inspect_exec(&exec_id).await {
-				Ok(res) => res.exit_code.unwrap_or(-1),
-				Err(err) => {
-					warn!("Failed to get exit code for exec_id={}, e={:?}", &exec_id, err);
-					-1
+		let mut per_container: Vec<(String, String, Vec<String>)> = stream::iter(containers)
+			.map(|container| self.scrape_container(container))
+			.buffer_unordered(self.scrape_concurrency)
+			.filter_map(|result| async move { result })
+			.collect()
+			.await;
+
+		// sort by container name, then container id as a tiebreaker, so output ordering is stable
+		// across runs regardless of which exec/fetch finished first - without the tiebreaker, a
+		// scaled-out service with several replicas sharing the same container name would still
+		// sort non-deterministically since sort_by is only stable relative to completion order
+		per_container.sort_by(|(left_name, left_id, _), (right_name, right_id, _)| {
+			left_name.cmp(right_name).then_with(|| left_id.cmp(right_id))
+		});
+
+		Some(Self::combine_relabeled_lines(per_container))
+	}
+
+	// Concatenates each container's relabeled lines, emitting each metric's `# HELP`/`# TYPE`
+	// lines only once (before its first sample) even when several containers expose the
+	// same metric name, since Prometheus rejects duplicate metadata for a metric.
+	fn combine_relabeled_lines(per_container: Vec<(String, String, Vec<String>)>) -> String {
+		let mut emitted_metadata: HashSet<(String, String)> = HashSet::new();
+		let mut combined = String::new();
+
+		for (_, _, lines) in per_container {
+			for line in lines {
+				if let Some(metadata_key) = metadata_metric_name(&line) {
+					if !emitted_metadata.insert(metadata_key) {
+						continue;
+					}
 				}
-			};
 
-			if exit_code != 0 || curl_output.is_none() {
-				warn!("Exit code for exec={} in container={} is {}, output={:?}", &exec_id, &container_id, exit_code, curl_output);
-				continue;
+				combined.push_str(&line);
+				combined.push('\n');
 			}
+		}
 
-			metrics += curl_output.unwrap().iter()
-				.map(|line| self.add_service_name_to_metric_line(line, aws_container_name))
-				.collect::<Vec<String>>()
-				.join("\n").as_str();
+		combined
+	}
+
+	async fn scrape_container(&self, container: ContainerSummary) -> Option<(String, String, Vec<String>)> {
+		match self.scrape_mode {
+			ScrapeMode::ExecCurl => self.scrape_container_via_exec(container).await,
+			ScrapeMode::DockerNetwork => self.scrape_container_via_network(container).await,
+		}
+	}
+
+	async fn scrape_container_via_exec(&self, container: ContainerSummary) -> Option<(String, String, Vec<String>)> {
+		let container_id = container.id.clone().unwrap();
+		let container_labels = container.labels.clone().unwrap_or(HashMap::new());
+		let aws_container_name = container_labels.get("com.amazonaws.ecs.container-name")
+			.unwrap_or(&UNKNOWN_SERVICE_NAME.to_string())
+			.to_string();
+		let injected_labels = self.build_injected_labels(&container_labels, &aws_container_name);
+
+		debug!("Scraping container={} via exec+curl", &container_id);
+		let curl_exec = self.create_docker_exec_for_curl(container, &container_id).await;
+
+		if let Err(err) = curl_exec {
+			warn!("Failed to create exec in container={:?}, e={:?}", &container_id, err);
+			return None;
 		}
 
-		Some(metrics)
+		let exec_id = curl_exec.unwrap().id;
+		let curl_output = self.start_curl_exec_return_logs(&container_id, &exec_id).await;
+		let exit_code: i64 = match self.docker.inspect_exec(&exec_id).await {
+			Ok(res) => res.exit_code.unwrap_or(-1),
+			Err(err) => {
+				warn!("Failed to get exit code for exec_id={}, e={:?}", &exec_id, err);
+				-1
+			}
+		};
+
+		if exit_code != 0 || curl_output.is_none() {
+			warn!("Exit code for exec={} in container={} is {}, output={:?}", &exec_id, &container_id, exit_code, curl_output);
+			return None;
+		}
+
+		let relabeled_lines = curl_output.unwrap().iter()
+			.map(|line| add_service_name_to_metric_line(line, &injected_labels))
+			.collect::<Vec<String>>();
+
+		Some((aws_container_name, container_id, relabeled_lines))
+	}
+
+	async fn scrape_container_via_network(&self, container: ContainerSummary) -> Option<(String, String, Vec<String>)> {
+		let container_id = container.id.clone().unwrap();
+		let container_labels = container.labels.clone().unwrap_or(HashMap::new());
+		let aws_container_name = container_labels.get("com.amazonaws.ecs.container-name")
+			.unwrap_or(&UNKNOWN_SERVICE_NAME.to_string())
+			.to_string();
+		let injected_labels = self.build_injected_labels(&container_labels, &aws_container_name);
+		let port_and_metric_path = container_labels.get(&self.label_has_metrics)
+			.unwrap_or(&DEFAULT_SERVICE_PORT_PATH.to_string())
+			.to_string();
+
+		let ip_address = match self.get_container_network_ip(&container_id).await {
+			Some(ip_address) => ip_address,
+			None => {
+				warn!("Could not find a network IP for container={}, skipping", &container_id);
+				return None;
+			}
+		};
+
+		let metrics_url = format!("http://{}:{}", ip_address, port_and_metric_path);
+		debug!("Scraping container={} via Docker network at {}", &container_id, &metrics_url);
+
+		let response = match self.http_client.get(&metrics_url).send().await {
+			Ok(response) => response,
+			Err(err) => {
+				warn!("Failed to GET {} for container={}, e={:?}", &metrics_url, &container_id, err);
+				return None;
+			}
+		};
+
+		let body = match response.text().await {
+			Ok(body) => body,
+			Err(err) => {
+				warn!("Failed to read response body from {} for container={}, e={:?}", &metrics_url, &container_id, err);
+				return None;
+			}
+		};
+
+		let relabeled_lines = body.split('\n')
+			.map(|line| add_service_name_to_metric_line(line, &injected_labels))
+			.collect::<Vec<String>>();
+
+		Some((aws_container_name, container_id, relabeled_lines))
+	}
+
+	fn build_injected_labels(&self, container_labels: &HashMap<String, String>, container_name: &str) -> Vec<(String, String)> {
+		let mut labels = vec![("container_name".to_string(), container_name.to_string())];
+		labels.extend(self.ecs_label_mapping.resolve(container_labels));
+		labels
+	}
+
+	async fn get_container_network_ip(&self, container_id: &String) -> Option<String> {
+		let inspect = match self.docker.inspect_container(container_id, None).await {
+			Ok(inspect) => inspect,
+			Err(err) => {
+				warn!("Failed to inspect container={}, e={:?}", container_id, err);
+				return None;
+			}
+		};
+
+		let mut networks = match inspect.network_settings.and_then(|network_settings| network_settings.networks) {
+			Some(networks) => networks,
+			None => return None,
+		};
+
+		// prefer the configured network by name; a container attached to more than one network
+		// (e.g. a custom network alongside the default ECS bridge) otherwise has no well-defined
+		// network to scrape, since HashMap iteration order isn't stable across process restarts
+		let network = match networks.remove(&self.docker_network_name) {
+			Some(network) => network,
+			None if networks.len() == 1 => networks.into_values().next().unwrap(),
+			None => {
+				warn!(
+					"Container={} has no network named '{}' and is attached to {} others; not scraping any of them",
+					container_id, self.docker_network_name, networks.len(),
+				);
+				return None;
+			}
+		};
+
+		network.ip_address.filter(|ip_address| !ip_address.is_empty())
 	}
 
 	async fn get_docker_containers_matching_label(&self) -> Result<Vec<ContainerSummary>, BollardError> {
@@ -120,31 +426,36 @@ impl ServiceMetricsExporter {
 		match self.docker.start_exec(&exec_id, None).await {
 			Ok(StartExecResults::Attached { output, .. }) => {
 				debug!("Started cURL in container={}", &container_id);
-				let log = output.try_collect().await;
-				if let Err(err) = log {
-					debug!("Failed to get output for container={}, e={:?}", &container_id, err);
-					return None;
-				}
 
-				let log: Vec<_> = log.unwrap();
+				// fold every frame of the multiplexed stream before splitting into lines, since a
+				// single response can span several `LogOutput` chunks and a line can straddle two of them
+				let buffers = output.try_fold((Vec::new(), Vec::new()), |(mut stdout_buf, mut stderr_buf): (Vec<u8>, Vec<u8>), frame| async move {
+					match frame {
+						LogOutput::StdOut { message } => stdout_buf.extend_from_slice(&message),
+						LogOutput::StdErr { message } => stderr_buf.extend_from_slice(&message),
+						LogOutput::StdIn { .. } | LogOutput::Console { .. } => {}
+					}
+					Ok((stdout_buf, stderr_buf))
+				}).await;
 
-				if log.is_empty() {
-					warn!("Found no output log for container={}", &container_id);
+				let (stdout_buf, stderr_buf) = match buffers {
+					Ok(buffers) => buffers,
+					Err(err) => {
+						debug!("Failed to get output for container={}, e={:?}", &container_id, err);
+						return None;
+					}
+				};
+
+				if !stderr_buf.is_empty() {
+					warn!("cURL wrote to stderr in container={}: {}", &container_id, String::from_utf8_lossy(&stderr_buf));
+				}
+
+				if stdout_buf.is_empty() {
+					warn!("Found no stdout output for container={}", &container_id);
 					return None;
 				}
 
-				let mut output_lines = vec![];
-				match &log[0] {
-					LogOutput::StdOut { message } => {
-						for line in String::from_utf8_lossy(message).split('\n') {
-							output_lines.push(line.to_string());
-						}
-					}
-					LogOutput::StdErr { .. } => {}
-					LogOutput::StdIn { .. } => {}
-					LogOutput::Console { .. } => {}
-				};
-				Some(output_lines)
+				Some(String::from_utf8_lossy(&stdout_buf).split('\n').map(|line| line.to_string()).collect())
 			}
 			Ok(StartExecResults::Detached) => {
 				warn!("Somehow failed to start cURL in container={} => detached", &container_id);
@@ -157,28 +468,202 @@ impl ServiceMetricsExporter {
 		}
 	}
 
-	fn add_service_name_to_metric_line(&self, line: &String, container_name: &str) -> String {
-		// return comment/meta lines unaltered
-		if line.trim().starts_with("#") {
-			return line.to_string();
-		}
+}
 
-		let service_label = format!("container_name={}", container_name);
+/// Splices `injected_labels` onto a single line of Prometheus exposition format. Comment and
+/// blank lines pass through unaltered; a sample line gets the labels spliced in as the first
+/// entries of its label block (creating one if it doesn't already have one).
+fn add_service_name_to_metric_line(line: &str, injected_labels: &[(String, String)]) -> String {
+	if line.trim().is_empty() {
+		return line.to_string();
+	}
 
-		// already has a label => add our label as the first one, including a trailing comma
-		if let Some(bracket_position) = line.find("{") {
-			let (line_left, line_right) = line.split_at(bracket_position + 1);
-			return format!("{}{},{}", line_left, service_label, line_right).to_string();
-		}
+	// comment lines (`# HELP ...`, `# TYPE ...`, or a bare `#` comment) carry no labels
+	if line.trim_start().starts_with('#') {
+		return line.to_string();
+	}
+
+	let injected_label_fragment = injected_labels.iter()
+		.map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+		.collect::<Vec<String>>()
+		.join(",");
+
+	// sample line: the metric name is the leading run of bytes up to the first `{` or whitespace
+	let name_end = line.find(|c: char| c == '{' || c.is_whitespace()).unwrap_or(line.len());
 
-		// no label yet => insert the whole label thingy
-		if let Some(space_pos) = line.find(" ") {
-			let (line_left, line_right) = line.split_at(space_pos);
-			return format!("{}{{{}}}{}", line_left, service_label, line_right).to_string();
+	if line[name_end..].starts_with('{') {
+		return match find_matching_close_brace(line, name_end) {
+			// empty block (`metric{}`) => no existing labels to comma-separate from
+			Some(close_brace_pos) if close_brace_pos == name_end + 1 => format!("{}{}{}", &line[..name_end + 1], injected_label_fragment, &line[name_end + 1..]),
+			// existing label block => splice ours in as the first labels, before the rest
+			Some(_close_brace_pos) => format!("{}{},{}", &line[..name_end + 1], injected_label_fragment, &line[name_end + 1..]),
+			None => {
+				warn!("Found unterminated label block, leaving line unaltered: {}", line);
+				line.to_string()
+			}
+		};
+	}
+
+	if name_end < line.len() {
+		// no label block yet => insert a whole new one right after the metric name
+		return format!("{}{{{}}}{}", &line[..name_end], injected_label_fragment, &line[name_end..]);
+	}
+
+	info!("Encountered a weird line, neither comment nor parsable metric, not attaching service name: {}", line);
+	line.to_string()
+}
+
+/// Scans a `{...}` label block starting at `line[open_brace_pos..]`, respecting `"`-quoted
+/// label values (including `\"` and `\\` escapes), and returns the index of the matching `}`.
+fn find_matching_close_brace(line: &str, open_brace_pos: usize) -> Option<usize> {
+	let bytes = line.as_bytes();
+	let mut in_quotes = false;
+	let mut i = open_brace_pos + 1;
+
+	while i < bytes.len() {
+		match bytes[i] {
+			b'\\' if in_quotes => i += 1, // skip the escaped character, whatever it is
+			b'"' => in_quotes = !in_quotes,
+			b'}' if !in_quotes => return Some(i),
+			_ => {}
 		}
+		i += 1;
+	}
 
-		info!("Encountered a weird line, neither comment nor parsable metric, not attaching service name: {}", line);
-		line.to_string()
+	None
+}
+
+fn escape_label_value(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// If `line` is a `# HELP <metric>` or `# TYPE <metric>` line, returns `(keyword, metric)`, e.g.
+/// `("HELP", "my_metric")`. Keyed on both so a metric's HELP line doesn't dedup-collide with its
+/// own TYPE line - they're different pieces of metadata and both need to be emitted once each.
+fn metadata_metric_name(line: &str) -> Option<(String, String)> {
+	let comment_body = line.trim_start().strip_prefix('#')?.trim_start();
+	let mut words = comment_body.split_whitespace();
+
+	let keyword = words.next()?;
+	if keyword != "HELP" && keyword != "TYPE" {
+		return None;
+	}
+
+	words.next().map(|name| (keyword.to_string(), name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn container_name_label(value: &str) -> Vec<(String, String)> {
+		vec![("container_name".to_string(), value.to_string())]
+	}
+
+	#[test]
+	fn add_service_name_to_metric_line_inserts_label_block_when_none_present() {
+		let line = add_service_name_to_metric_line("requests_total 5", &container_name_label("app"));
+		assert_eq!(line, "requests_total{container_name=\"app\"} 5");
+	}
+
+	#[test]
+	fn add_service_name_to_metric_line_prepends_to_existing_label_block() {
+		let line = add_service_name_to_metric_line("requests_total{method=\"GET\"} 5", &container_name_label("app"));
+		assert_eq!(line, "requests_total{container_name=\"app\",method=\"GET\"} 5");
+	}
+
+	#[test]
+	fn add_service_name_to_metric_line_handles_empty_label_block_without_trailing_comma() {
+		let line = add_service_name_to_metric_line("requests_total{} 5", &container_name_label("app"));
+		assert_eq!(line, "requests_total{container_name=\"app\"} 5");
+	}
+
+	#[test]
+	fn add_service_name_to_metric_line_handles_label_value_containing_brace_and_space() {
+		let line = add_service_name_to_metric_line("requests_total{path=\"/a {b} c\"} 1", &container_name_label("app"));
+		assert_eq!(line, "requests_total{container_name=\"app\",path=\"/a {b} c\"} 1");
+	}
+
+	#[test]
+	fn add_service_name_to_metric_line_handles_escaped_quote_in_label_value() {
+		let line = add_service_name_to_metric_line("requests_total{path=\"a\\\"b\"} 1", &container_name_label("app"));
+		assert_eq!(line, "requests_total{container_name=\"app\",path=\"a\\\"b\"} 1");
 	}
 
+	#[test]
+	fn add_service_name_to_metric_line_escapes_quotes_and_backslashes_in_injected_value() {
+		let line = add_service_name_to_metric_line("requests_total 5", &container_name_label("weird\"name\\"));
+		assert_eq!(line, "requests_total{container_name=\"weird\\\"name\\\\\"} 5");
+	}
+
+	#[test]
+	fn add_service_name_to_metric_line_injects_multiple_labels_in_order() {
+		let labels = vec![("container_name".to_string(), "app".to_string()), ("cluster".to_string(), "prod".to_string())];
+		let line = add_service_name_to_metric_line("requests_total 5", &labels);
+		assert_eq!(line, "requests_total{container_name=\"app\",cluster=\"prod\"} 5");
+	}
+
+	#[test]
+	fn add_service_name_to_metric_line_leaves_comment_and_blank_lines_unaltered() {
+		assert_eq!(add_service_name_to_metric_line("# HELP requests_total docs", &container_name_label("app")), "# HELP requests_total docs");
+		assert_eq!(add_service_name_to_metric_line("", &container_name_label("app")), "");
+	}
+
+	#[test]
+	fn add_service_name_to_metric_line_leaves_unterminated_label_block_unaltered() {
+		let line = add_service_name_to_metric_line("requests_total{method=\"GET\" 5", &container_name_label("app"));
+		assert_eq!(line, "requests_total{method=\"GET\" 5");
+	}
+
+	#[test]
+	fn find_matching_close_brace_skips_braces_and_escapes_inside_quotes() {
+		let line = "metric{a=\"{not a close\\\" }\"} 1";
+		let open_brace_pos = line.find('{').unwrap();
+		let close_brace_pos = find_matching_close_brace(line, open_brace_pos).unwrap();
+		assert_eq!(&line[close_brace_pos..close_brace_pos + 1], "}");
+		assert_eq!(&line[close_brace_pos + 1..], " 1");
+	}
+
+	#[test]
+	fn find_matching_close_brace_returns_none_when_unterminated() {
+		assert_eq!(find_matching_close_brace("metric{a=\"x\"", 6), None);
+	}
+
+	#[test]
+	fn escape_label_value_escapes_backslashes_before_quotes() {
+		assert_eq!(escape_label_value("a\\b\"c"), "a\\\\b\\\"c");
+	}
+
+	#[test]
+	fn metadata_metric_name_parses_help_and_type_lines() {
+		assert_eq!(metadata_metric_name("# HELP requests_total total requests"), Some(("HELP".to_string(), "requests_total".to_string())));
+		assert_eq!(metadata_metric_name("# TYPE requests_total counter"), Some(("TYPE".to_string(), "requests_total".to_string())));
+		assert_eq!(metadata_metric_name("requests_total 5"), None);
+		assert_eq!(metadata_metric_name("# just a comment"), None);
+	}
+
+	#[test]
+	fn combine_relabeled_lines_emits_shared_metric_metadata_once() {
+		let per_container = vec![
+			("app".to_string(), "container-a".to_string(), vec![
+				"# HELP requests_total total requests".to_string(),
+				"# TYPE requests_total counter".to_string(),
+				"requests_total{container_name=\"app\"} 1".to_string(),
+			]),
+			("app".to_string(), "container-b".to_string(), vec![
+				"# HELP requests_total total requests".to_string(),
+				"# TYPE requests_total counter".to_string(),
+				"requests_total{container_name=\"app\"} 2".to_string(),
+			]),
+		];
+
+		let combined = ServiceMetricsExporter::combine_relabeled_lines(per_container);
+
+		assert_eq!(combined, concat!(
+			"# HELP requests_total total requests\n",
+			"# TYPE requests_total counter\n",
+			"requests_total{container_name=\"app\"} 1\n",
+			"requests_total{container_name=\"app\"} 2\n",
+		));
+	}
 }